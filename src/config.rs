@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::Error;
+
+const NEBTOWN_UPLOAD_URL: &'static str = "http://nebtown.info/ss/";
+// --account's default, which stays usable whatever accounts the config defines
+const DEFAULT_ACCOUNT: &'static str = "anon";
+
+/// Settings read from the toml config file, eg.
+///
+/// ```toml
+/// [accounts.work]
+/// upload_url = "https://screenshots.example.com/"
+/// token = "hunter2"
+/// directory = "D:/screenshots/work"
+/// ```
+#[derive(Deserialize, Default)]
+pub struct Config {
+    #[serde(default)]
+    pub accounts: HashMap<String, Account>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Account {
+    /// Folder on the server to upload into, defaults to the account's name
+    #[serde(default)]
+    pub folder: String,
+    #[serde(default = "default_upload_url")]
+    pub upload_url: String,
+    /// Sent as a bearer token with every request
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Where screenshots are saved locally, unless --directory is given
+    #[serde(default)]
+    pub directory: Option<PathBuf>,
+}
+
+fn default_upload_url() -> String {
+    NEBTOWN_UPLOAD_URL.to_string()
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, Error> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("couldn't read {}: {}", path.display(), e)))?;
+        toml::from_str(&contents)
+            .map_err(|e| Error::Config(format!("couldn't parse {}: {}", path.display(), e)))
+    }
+
+    /// The named account. Without any configured accounts, that's a nebtown folder of that name;
+    /// once accounts are defined, only the default `anon` still falls back to nebtown, and any
+    /// other unknown name is an error rather than a silent anon upload.
+    pub fn account(&self, name: &str) -> Result<Account, Error> {
+        let mut account = match self.accounts.get(name) {
+            Some(account) => account.clone(),
+            None if self.accounts.is_empty() || name == DEFAULT_ACCOUNT => Account::nebtown(name),
+            None => {
+                let mut known: Vec<&str> = self.accounts.keys().map(String::as_str).collect();
+                known.sort();
+                return Err(Error::Config(format!(
+                    "no account named {} (configured: {})",
+                    name,
+                    known.join(", ")
+                )));
+            }
+        };
+        if account.folder.is_empty() {
+            account.folder = name.to_string();
+        }
+        Ok(account)
+    }
+}
+
+impl Account {
+    pub fn nebtown(folder: &str) -> Account {
+        Account {
+            folder: folder.to_string(),
+            upload_url: default_upload_url(),
+            token: None,
+            directory: None,
+        }
+    }
+
+    /// Where `filename` can be viewed once uploaded.
+    pub fn file_url(&self, filename: &str) -> String {
+        format!(
+            "{}/{}/{}",
+            self.upload_url.trim_end_matches('/'),
+            self.folder,
+            filename
+        )
+    }
+
+    /// Where `filename` is uploaded to, or deleted from.
+    pub fn endpoint(&self, filename: &str) -> String {
+        format!(
+            "{}/?folder_name={}&file_name={}",
+            self.upload_url.trim_end_matches('/'),
+            self.folder,
+            filename
+        )
+    }
+
+    pub(crate) fn authorize(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+}
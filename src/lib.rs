@@ -0,0 +1,1777 @@
+extern crate apng_encoder;
+extern crate chrono;
+extern crate device_query;
+extern crate image;
+extern crate oxipng;
+extern crate piston_window;
+extern crate reqwest;
+extern crate scrap;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate toml;
+extern crate winit;
+#[macro_use]
+extern crate lazy_static;
+
+mod config;
+
+pub use config::{Account, Config};
+
+use apng_encoder::{Color, Delay, Encoder, Frame, Meta};
+use device_query::{DeviceQuery, DeviceState, Keycode};
+use image::png::PNGEncoder;
+use image::{
+    ColorType, GenericImage, GenericImageView, RgbImage, RgbaImage,
+};
+use image::imageops::FilterType;
+use image::buffer::ConvertBuffer;
+use piston_window::*;
+use scrap::{Capturer, Display};
+use std::cell::RefCell;
+use std::cmp::max;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::ErrorKind::WouldBlock;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use winit::{EventsLoop};
+
+const SELECTION_COLOUR: [f32; 4] = [0.0, 0.0, 1.0, 1.0];
+const TOO_SMALL_COLOUR: [f32; 4] = [1.0, 0.0, 0.0, 1.0];
+const SWATCH_SIZE: f64 = 24.0;
+const SWATCH_OFFSET: f64 = 16.0;
+// size of one dot of the hex label's bitmap font
+const GLYPH_DOT: f64 = 3.0;
+// --upscale-small-crops enlarges crops by the smallest whole factor that brings their
+// longest side to at least this many pixels
+const UPSCALE_THRESHOLD: u32 = 128;
+const CAPTURER_ATTEMPTS: u32 = 5;
+const LAST_UPLOAD_FILENAME: &'static str = ".ncscreenier_last_upload.json";
+lazy_static! {
+    static ref ONE_FRAME: Duration = Duration::new(1, 0) / 60;
+    static ref DURATION_1MS: Duration = Duration::new(0, 1);
+    static ref CAPTURER_RETRY_PAUSE: Duration = Duration::from_millis(200);
+}
+
+#[cfg(debug_assertions)]
+const DEBUGGING: bool = true;
+#[cfg(not(debug_assertions))]
+const DEBUGGING: bool = false;
+
+// Debug output goes to stderr, keeping stdout clean for --json
+macro_rules! d {
+    ($($arg:tt)*) => {
+      if DEBUGGING {
+        ($($arg)*);
+      }
+    };
+}
+
+// Decorative progress output, silenced under --json so stdout stays machine-readable
+static HUMAN_OUTPUT: AtomicBool = AtomicBool::new(true);
+// keeps spool filenames unique across recordings started within the same second
+static SPOOLS_CREATED: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether progress messages are printed to stdout (the default), or suppressed for scripting.
+pub fn set_human_output(enabled: bool) {
+    HUMAN_OUTPUT.store(enabled, Ordering::Relaxed);
+}
+
+/// See `set_human_output`; lets the binary's own messages follow the same setting.
+pub fn human_output() -> bool {
+    HUMAN_OUTPUT.load(Ordering::Relaxed)
+}
+
+macro_rules! say {
+    ($($arg:tt)*) => {
+      if HUMAN_OUTPUT.load(Ordering::Relaxed) {
+        print!($($arg)*);
+        io::stdout().flush().expect("error flushing stdout");
+      }
+    };
+}
+
+macro_rules! sayln {
+    ($($arg:tt)*) => {
+      if HUMAN_OUTPUT.load(Ordering::Relaxed) {
+        println!($($arg)*);
+      }
+    };
+}
+
+// Errors still get reported under --json, just on stderr instead
+macro_rules! complain {
+    ($($arg:tt)*) => {
+      if HUMAN_OUTPUT.load(Ordering::Relaxed) {
+        println!($($arg)*);
+      } else {
+        eprintln!($($arg)*);
+      }
+    };
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Enumerating or reading from the displays failed
+    Capture(io::Error),
+    /// Encoding or optimizing the png failed
+    Encode(String),
+    /// Reading or writing to disk failed
+    Io(io::Error),
+    /// The output directory or file couldn't be created
+    Save(PathBuf, io::Error),
+    /// The server couldn't be reached, or rejected the request
+    Upload(String),
+    /// The requested region doesn't overlap any captured display
+    Selection(String),
+    /// The config file couldn't be read
+    Config(String),
+    /// The window for cropping or colour picking couldn't be opened
+    Window(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Capture(e) => write!(f, "couldn't capture screen: {}", e),
+            Error::Encode(e) => write!(f, "couldn't encode image: {}", e),
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Save(path, e) => write!(f, "couldn't write {}: {}", path.display(), e),
+            Error::Upload(e) => write!(f, "upload failed: {}", e),
+            Error::Selection(e) => write!(f, "invalid selection: {}", e),
+            Error::Config(e) => write!(f, "invalid config: {}", e),
+            Error::Window(e) => write!(f, "couldn't open window: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+pub struct SavedScreenshot {
+    pub filename: String,
+    pub filepath: PathBuf,
+    pub width: u32,
+    pub height: u32,
+    pub frames: u32,
+    pub bytes: u64,
+}
+
+#[derive(Clone, Copy)]
+pub enum Selection {
+    /// Drag a crop rectangle in a window laid over the desktop
+    Interactive,
+    /// Crop to this region in desktop coordinates, without opening a window
+    Region {
+        x: i32,
+        y: i32,
+        width: u32,
+        height: u32,
+    },
+    /// Keep the whole desktop uncropped, without opening a window
+    Full,
+}
+
+#[derive(Clone, Copy)]
+pub struct CaptureOptions {
+    /// Upper bound on how many frames per second are recorded while Shift is held
+    pub fps: Option<u32>,
+    /// Selections smaller than this (width, height) are refused by `present_for_cropping`
+    pub min_size: Option<(u32, u32)>,
+    pub selection: Selection,
+}
+
+impl Default for CaptureOptions {
+    fn default() -> Self {
+        CaptureOptions {
+            fps: None,
+            min_size: None,
+            selection: Selection::Interactive,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct SaveOptions {
+    /// How many times an animation plays, 0 loops forever
+    pub loops: u32,
+    /// Viewers without APNG support show an animation's first frame; this shows its last instead
+    pub last_frame_fallback: bool,
+    /// Integer-upscale tiny crops (nearest neighbour) so small icons stay legible
+    pub upscale_small_crops: bool,
+    /// Strip every non-essential png chunk (text, time, exif...) when optimizing
+    pub strip_metadata: bool,
+}
+
+impl SaveOptions {
+    fn scale_for(&self, rect: &Rect) -> u32 {
+        let longest_side = max(rect.width(), rect.height()).max(1);
+        if self.upscale_small_crops && longest_side < UPSCALE_THRESHOLD {
+            UPSCALE_THRESHOLD.div_ceil(longest_side)
+        } else {
+            1
+        }
+    }
+}
+
+/// Captures every display, lets the user crop it, and saves the crop into `directory`.
+/// Returns `Ok(None)` if the user cancelled the crop.
+pub fn screenshot_and_save(
+    directory: &Path,
+    capture_options: &CaptureOptions,
+    options: &SaveOptions,
+) -> Result<Option<SavedScreenshot>, Error> {
+    if let Selection::Interactive = capture_options.selection {
+        let screenshot = capture_screenshot(capture_options)?;
+        if let Some(rect) = select(&screenshot, capture_options)? {
+            save_screenshot(screenshot, &rect, directory, options).map(Some)
+        } else {
+            sayln!("Closing screenshot due to right click");
+            Ok(None)
+        }
+    } else {
+        record_and_save(directory, capture_options, options).map(Some)
+    }
+}
+
+/// Like `screenshot_and_save` for the non-interactive selections: since the crop is known up
+/// front, recorded frames are cropped and spooled to disk as they arrive, rather than all
+/// being held in memory until encoding.
+fn record_and_save(
+    directory: &Path,
+    capture_options: &CaptureOptions,
+    options: &SaveOptions,
+) -> Result<SavedScreenshot, Error> {
+    let desktop = start_capture()?;
+    let rect = fixed_selection(
+        capture_options.selection,
+        desktop.min_x,
+        desktop.min_y,
+        (desktop.max_x - desktop.min_x) as u32,
+        (desktop.max_y - desktop.min_y) as u32,
+    )?
+    .expect("record_and_save needs a non-interactive selection");
+    let mut spool = FrameSpool::create(rect, options.scale_for(&rect))?;
+    let (image, delays) = record(&desktop, capture_options, &mut spool)?;
+    save_recording(&image, delays, spool, directory, options)
+}
+
+/// The rest of `record_and_save` once recording's done: saves the separately kept `first`
+/// frame followed by whatever was spooled.
+fn save_recording(
+    first: &RgbaImage,
+    delays: Vec<u16>,
+    mut spool: FrameSpool,
+    directory: &Path,
+    options: &SaveOptions,
+) -> Result<SavedScreenshot, Error> {
+    let (rect, scale) = (spool.rect, spool.scale);
+    let fallback = if options.last_frame_fallback {
+        spool.latest.take().map(|last| crop_to_rgb(&last, &rect, scale))
+    } else {
+        None
+    };
+    let frames = Frames {
+        first,
+        rest_count: spool.frames,
+        rest: spool.into_frames()?,
+        fallback,
+        delays,
+    };
+    save_frames(frames, &rect, directory, options)
+}
+
+/// Picks the part of `screenshot` to keep; only `Selection::Interactive` opens a window.
+pub fn select(
+    screenshot: &PresentabeScreenshot,
+    options: &CaptureOptions,
+) -> Result<Option<Rect>, Error> {
+    let (image_width, image_height) = screenshot.image.dimensions();
+    match options.selection {
+        Selection::Interactive => present_for_cropping(screenshot, options.min_size),
+        selection => fixed_selection(selection, screenshot.x, screenshot.y, image_width, image_height),
+    }
+}
+
+/// The crop for a non-interactive `selection`, of a desktop image positioned at (`x`, `y`).
+fn fixed_selection(
+    selection: Selection,
+    image_x: i32,
+    image_y: i32,
+    image_width: u32,
+    image_height: u32,
+) -> Result<Option<Rect>, Error> {
+    match selection {
+        Selection::Interactive => Ok(None),
+        Selection::Full => Ok(Some(Rect {
+            top_left: (0, 0),
+            bottom_right: (image_width, image_height),
+        })),
+        Selection::Region {
+            x,
+            y,
+            width,
+            height,
+        } => {
+            // translate from desktop coordinates into the composite image, clamped to its edges.
+            // i64 since a region near i32::MAX would overflow once its size is added
+            let clamp = |position: i64, limit: u32| position.max(0).min(limit as i64) as u32;
+            let (image_left, image_top) = (x as i64 - image_x as i64, y as i64 - image_y as i64);
+            let left = clamp(image_left, image_width);
+            let top = clamp(image_top, image_height);
+            let right = clamp(image_left + width as i64, image_width);
+            let bottom = clamp(image_top + height as i64, image_height);
+            if right <= left || bottom <= top {
+                return Err(Error::Selection(format!(
+                    "region {},{} {}x{} is outside the captured displays",
+                    x, y, width, height
+                )));
+            }
+            Ok(Some(Rect {
+                top_left: (left, top),
+                bottom_right: (right, bottom),
+            }))
+        }
+    }
+}
+
+/// Crops `screenshot` to `rect`, and writes it into `directory` as a png (or apng, if animated).
+pub fn save_screenshot(
+    screenshot: PresentabeScreenshot,
+    rect: &Rect,
+    directory: &Path,
+    options: &SaveOptions,
+) -> Result<SavedScreenshot, Error> {
+    let scale = options.scale_for(rect);
+    let additional_frames = screenshot.additional_images.len() as u32;
+    let fallback = if options.last_frame_fallback {
+        screenshot
+            .additional_images
+            .last()
+            .map(|last| crop_to_rgb(last, rect, scale))
+    } else {
+        None
+    };
+    let rest = screenshot
+        .additional_images
+        .into_iter()
+        .map(|frame_image| Ok(crop_to_rgb(&frame_image, rect, scale)));
+    let frames = Frames {
+        first: &screenshot.image,
+        rest,
+        rest_count: additional_frames,
+        fallback,
+        delays: screenshot.delays,
+    };
+    save_frames(frames, rect, directory, options)
+}
+
+/// An animation on its way to disk: the uncropped first frame, then the rest already cropped.
+struct Frames<'a, I> {
+    first: &'a RgbaImage,
+    rest: I,
+    rest_count: u32,
+    /// The cropped frame shown by viewers without APNG support, if not `first`
+    fallback: Option<RgbImage>,
+    /// Milliseconds each frame is shown for
+    delays: Vec<u16>,
+}
+
+/// Writes `frames` (with the first cropped to `rect`) into `directory`.
+fn save_frames<I: Iterator<Item = Result<RgbImage, Error>>>(
+    frames: Frames<I>,
+    rect: &Rect,
+    directory: &Path,
+    options: &SaveOptions,
+) -> Result<SavedScreenshot, Error> {
+    std::fs::create_dir_all(directory).map_err(|e| Error::Save(directory.to_path_buf(), e))?;
+    let filename = format!("{}.png", chrono::Local::now().format("%Y_%m_%d_%H-%M-%S"));
+    let filepath = directory.join(&filename);
+    say!(
+        "Saving crop {},{} -> {}, {} to {}...",
+        rect.top_left.0,
+        rect.top_left.1,
+        rect.bottom_right.0,
+        rect.bottom_right.1,
+        filepath.display()
+    );
+    let save_error = |e| Error::Save(filepath.clone(), e);
+    let frame_count = 1 + frames.rest_count;
+    let scale = options.scale_for(rect);
+    if frames.rest_count == 0 {
+        let optimized_buffer = encode_png(frames.first, rect, options)?;
+        let mut file = File::create(&filepath).map_err(save_error)?;
+        file.write_all(&optimized_buffer).map_err(save_error)?;
+    } else {
+        let mut file = io::BufWriter::new(File::create(&filepath).map_err(save_error)?);
+        write_apng(frames, rect, options, &mut file)?;
+        file.flush().map_err(save_error)?;
+    }
+    sayln!(" saved.");
+    let bytes = std::fs::metadata(&filepath).map(|m| m.len()).unwrap_or(0);
+    Ok(SavedScreenshot {
+        filename,
+        filepath,
+        width: rect.width() * scale,
+        height: rect.height() * scale,
+        frames: frame_count,
+        bytes,
+    })
+}
+
+fn crop_to_rgb(image: &RgbaImage, rect: &Rect, scale: u32) -> RgbImage {
+    let cropped = image
+        .view(rect.top_left.0, rect.top_left.1, rect.width(), rect.height())
+        .to_image();
+    if scale > 1 {
+        image::imageops::resize(
+            &cropped,
+            rect.width() * scale,
+            rect.height() * scale,
+            FilterType::Nearest,
+        )
+        .convert()
+    } else {
+        cropped.convert()
+    }
+}
+
+/// Crops `image` to `rect`, returning the optimized png bytes.
+pub fn encode_png(image: &RgbaImage, rect: &Rect, options: &SaveOptions) -> Result<Vec<u8>, Error> {
+    let cropped_image = crop_to_rgb(image, rect, options.scale_for(rect));
+
+    let mut png_buffer = Vec::new();
+    let (width, height) = cropped_image.dimensions();
+    PNGEncoder::new(png_buffer.by_ref())
+        .encode(&cropped_image.into_raw(), width, height, ColorType::Rgb8)
+        .map_err(|e| Error::Encode(format!("error encoding pixels as PNG: {}", e)))?;
+
+    let mut oxipng_options = oxipng::Options::from_preset(2);
+    oxipng_options.verbosity = None;
+    if options.strip_metadata {
+        oxipng_options.strip = oxipng::Headers::Safe;
+    }
+    oxipng::optimize_from_memory(&png_buffer, &oxipng_options)
+        .map_err(|e| Error::Encode(format!("error optimizing png: {}", e)))
+}
+
+/// Encodes `frames` as an apng cropped to `rect`. Their `fallback`, if given, is stored as the
+/// default image shown by viewers without APNG support, instead of the first frame doubling as it.
+fn write_apng<W: Write, I: Iterator<Item = Result<RgbImage, Error>>>(
+    frames: Frames<I>,
+    rect: &Rect,
+    options: &SaveOptions,
+    output: &mut W,
+) -> Result<(), Error> {
+    let scale = options.scale_for(rect);
+    let mut encoder = Encoder::create(
+        output,
+        Meta {
+            color: Color::RGB(8),
+            frames: 1 + frames.rest_count,
+            width: rect.width() * scale,
+            height: rect.height() * scale,
+            plays: if options.loops == 0 { None } else { Some(options.loops) },
+        },
+    )
+    .map_err(|e| Error::Encode(format!("failed to create apng encoder: {:?}", e)))?;
+
+    if let Some(fallback) = frames.fallback {
+        encoder
+            .write_default_image(&fallback, None, None)
+            .map_err(|e| Error::Encode(format!("failed to write apng fallback image: {:?}", e)))?;
+    }
+
+    let mut delays = frames.delays.into_iter();
+    let first = crop_to_rgb(frames.first, rect, scale);
+    for cropped_frame in std::iter::once(Ok(first)).chain(frames.rest) {
+        encoder
+            .write_frame(
+                &cropped_frame?.into_raw(),
+                Some(&Frame {
+                    delay: Some(Delay {
+                        numerator: delays.next().unwrap_or(0),
+                        denominator: 1000,
+                    }),
+                    ..Default::default()
+                }),
+                None,
+                None,
+            )
+            .map_err(|e| Error::Encode(format!("failed to write apng frame: {:?}", e)))?;
+    }
+    encoder
+        .finish()
+        .map_err(|e| Error::Encode(format!("failed to finish apng: {:?}", e)))
+}
+
+/// Somewhere for `record` to put each frame after the first.
+trait FrameSink {
+    fn push(&mut self, frame: RgbaImage) -> Result<(), Error>;
+    /// The most recent frame, used to fill in displays that haven't produced a new one
+    fn last(&self) -> Option<&RgbaImage>;
+}
+
+impl FrameSink for Vec<RgbaImage> {
+    fn push(&mut self, frame: RgbaImage) -> Result<(), Error> {
+        Vec::push(self, frame);
+        Ok(())
+    }
+
+    fn last(&self) -> Option<&RgbaImage> {
+        self.as_slice().last()
+    }
+}
+
+/// Deletes the file at its path when dropped.
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.0).ok();
+    }
+}
+
+/// Crops frames as they're recorded and appends their raw pixels to a temporary file,
+/// only keeping the latest uncropped frame in memory.
+struct FrameSpool {
+    rect: Rect,
+    scale: u32,
+    file: TempFile,
+    writer: io::BufWriter<File>,
+    frames: u32,
+    latest: Option<RgbaImage>,
+}
+
+impl FrameSpool {
+    fn create(rect: Rect, scale: u32) -> Result<FrameSpool, Error> {
+        let path = std::env::temp_dir().join(format!(
+            "ncscreenier-{}-{}-{}.frames",
+            std::process::id(),
+            chrono::Local::now().format("%Y_%m_%d_%H-%M-%S"),
+            SPOOLS_CREATED.fetch_add(1, Ordering::Relaxed)
+        ));
+        let writer = io::BufWriter::new(
+            File::create(&path).map_err(|e| Error::Save(path.clone(), e))?,
+        );
+        Ok(FrameSpool {
+            rect,
+            scale,
+            file: TempFile(path),
+            writer,
+            frames: 0,
+            latest: None,
+        })
+    }
+
+    fn into_frames(mut self) -> Result<SpooledFrames, Error> {
+        self.writer.flush()?;
+        let reader = io::BufReader::new(File::open(&self.file.0)?);
+        Ok(SpooledFrames {
+            width: self.rect.width() * self.scale,
+            height: self.rect.height() * self.scale,
+            remaining: self.frames,
+            reader,
+            _file: self.file,
+        })
+    }
+}
+
+impl FrameSink for FrameSpool {
+    fn push(&mut self, frame: RgbaImage) -> Result<(), Error> {
+        self.writer
+            .write_all(&crop_to_rgb(&frame, &self.rect, self.scale))?;
+        self.frames += 1;
+        self.latest = Some(frame);
+        Ok(())
+    }
+
+    fn last(&self) -> Option<&RgbaImage> {
+        self.latest.as_ref()
+    }
+}
+
+/// Reads a `FrameSpool`'s cropped frames back one at a time.
+struct SpooledFrames {
+    width: u32,
+    height: u32,
+    remaining: u32,
+    reader: io::BufReader<File>,
+    _file: TempFile,
+}
+
+impl Iterator for SpooledFrames {
+    type Item = Result<RgbImage, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let mut buffer = vec![0; (self.width * self.height * 3) as usize];
+        Some(
+            io::Read::read_exact(&mut self.reader, &mut buffer)
+                .map_err(Error::Io)
+                .map(|_| RgbImage::from_raw(self.width, self.height, buffer).unwrap()),
+        )
+    }
+}
+
+pub enum CopyFormat {
+    Plain,
+    Markdown,
+    BBCode,
+    Html,
+}
+
+impl CopyFormat {
+    pub fn format(&self, url: &str) -> String {
+        match self {
+            CopyFormat::Plain => url.to_string(),
+            CopyFormat::Markdown => format!("![]({})", url),
+            CopyFormat::BBCode => format!("[img]{}[/img]", url),
+            CopyFormat::Html => format!("<img src=\"{}\">", url),
+        }
+    }
+}
+
+impl std::str::FromStr for CopyFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(CopyFormat::Plain),
+            "markdown" => Ok(CopyFormat::Markdown),
+            "bbcode" => Ok(CopyFormat::BBCode),
+            "html" => Ok(CopyFormat::Html),
+            _ => Err(format!(
+                "Unknown --copy-format '{}', expected plain, markdown, bbcode or html",
+                s
+            )),
+        }
+    }
+}
+
+/// Uploads `filepath` to `account`'s server, retrying on connection errors.
+/// The returned url is known up front too, from `account.file_url`.
+pub fn upload(
+    filename: &str,
+    filepath: &Path,
+    account: &Account,
+    retries: u8,
+) -> Result<String, Error> {
+    let url = account.file_url(filename);
+    say!("Uploading to {} ...", url);
+
+    let form = reqwest::multipart::Form::new()
+        .file("file", &filepath)?;
+    let mut res = match account
+        .authorize(reqwest::Client::new().post(&account.endpoint(filename)))
+        .multipart(form)
+        .send()
+    {
+        Ok(success_response) => success_response,
+        Err(e) => {
+            complain!(" upload error! {:?}", e);
+            return if retries > 0 {
+                std::thread::sleep(Duration::from_secs(max((5 - retries).into(), 1)));
+                upload(filename, filepath, account, retries - 1)
+            } else {
+                complain!("Upload failed, giving up :(");
+                Err(Error::Upload(e.to_string()))
+            };
+        }
+    };
+    if res.status() == 200 {
+        sayln!(" done!");
+        Ok(url)
+    } else {
+        complain!(" error! {:?}, {:?}", res.status(), res.headers());
+        complain!("{:?}", res.text().unwrap_or("??".to_string()));
+        Err(Error::Upload(format!("server responded {}", res.status())))
+    }
+}
+
+/// Where the last upload is recorded: beside the config file rather than in an output
+/// directory, so `--delete-last` finds it whichever account or `--directory` made the upload.
+pub fn last_upload_path(config_path: &Path) -> PathBuf {
+    config_path.with_file_name(LAST_UPLOAD_FILENAME)
+}
+
+/// Records `saved` at `state_path` (see `last_upload_path`), for `delete_last_upload`.
+pub fn remember_upload(state_path: &Path, saved: &SavedScreenshot, account: &str, url: &str) {
+    let state = json!({
+        "filename": saved.filename,
+        "filepath": saved.filepath.to_string_lossy(),
+        "account": account,
+        "url": url,
+    });
+    if let Err(e) = std::fs::write(state_path, state.to_string()) {
+        complain!("Couldn't record last upload: {}", e);
+    }
+}
+
+/// Deletes the upload recorded at `state_path` by `remember_upload`, from both its account's
+/// server and the local path it was saved to.
+pub fn delete_last_upload(state_path: &Path, config: &Config) -> bool {
+    let state: serde_json::Value = match std::fs::read_to_string(state_path)
+        .map_err(|e| e.to_string())
+        .and_then(|contents| serde_json::from_str(&contents).map_err(|e| e.to_string()))
+    {
+        Ok(state) => state,
+        Err(e) => {
+            complain!("No previous upload recorded in {} ({})", state_path.display(), e);
+            return false;
+        }
+    };
+    let field = |name: &str| state[name].as_str().unwrap_or("").to_string();
+    let (filename, filepath, account) = (field("filename"), field("filepath"), field("account"));
+    if filename.is_empty() || filepath.is_empty() || account.is_empty() {
+        complain!(
+            "{} is missing the last upload's filename, filepath or account",
+            state_path.display()
+        );
+        return false;
+    }
+
+    let account = match config.account(&account) {
+        Ok(account) => account,
+        Err(e) => {
+            complain!("Can't delete {}: {}", filename, e);
+            return false;
+        }
+    };
+    if !delete_upload(&filename, &account, 4) {
+        return false;
+    }
+    match std::fs::remove_file(&filepath) {
+        Ok(_) => sayln!("Removed local file {}", filepath),
+        Err(e) => complain!("Couldn't remove local file {}: {}", filepath, e),
+    }
+    std::fs::remove_file(state_path).ok();
+    true
+}
+
+fn delete_upload(filename: &str, account: &Account, retries: u8) -> bool {
+    say!("Deleting {} ...", account.file_url(filename));
+
+    let res = match account
+        .authorize(reqwest::Client::new().delete(&account.endpoint(filename)))
+        .send()
+    {
+        Ok(success_response) => success_response,
+        Err(e) => {
+            complain!(" delete error! {:?}", e);
+            return if retries > 0 {
+                std::thread::sleep(Duration::from_secs(max((5 - retries).into(), 1)));
+                delete_upload(filename, account, retries - 1)
+            } else {
+                complain!("Delete failed, giving up :(");
+                false
+            };
+        }
+    };
+    if res.status().is_success() {
+        sayln!(" server acknowledged deletion.");
+        true
+    } else {
+        complain!(" server refused deletion! {:?}", res.status());
+        false
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Rect {
+    pub top_left: (u32, u32),
+    pub bottom_right: (u32, u32),
+}
+
+impl Rect {
+    pub fn width(&self) -> u32 {
+        self.bottom_right.0 - self.top_left.0
+    }
+
+    pub fn height(&self) -> u32 {
+        self.bottom_right.1 - self.top_left.1
+    }
+}
+
+/// Shows the screenshot in a borderless window over the desktop, returning the dragged selection.
+pub fn present_for_cropping(
+    screenshot: &PresentabeScreenshot,
+    min_size: Option<(u32, u32)>,
+) -> Result<Option<Rect>, Error> {
+    let mut start_pos: (f64, f64) = (0.0, 0.0);
+    let mut last_pos: (f64, f64) = (0.0, 0.0);
+    let mut is_mouse_down = false;
+
+    let (mut window, screenshot_texture, dpi_factor) = open_screenshot_window(screenshot)?;
+
+    while let Some(e) = window.next() {
+        let e: piston_window::Event = e;
+
+        window.draw_2d(&e, |c, gl, _device| {
+            image(&screenshot_texture, c.transform, gl);
+            if start_pos.0 < last_pos.0 && start_pos.1 < last_pos.1 {
+                let colour = if is_too_small(start_pos, last_pos, dpi_factor, min_size) {
+                    TOO_SMALL_COLOUR
+                } else {
+                    SELECTION_COLOUR
+                };
+                rectangle::Rectangle::new_border(colour, 1.0).draw(
+                    rectangle::rectangle_by_corners(
+                        start_pos.0.into(),
+                        start_pos.1.into(),
+                        last_pos.0.into(),
+                        last_pos.1.into(),
+                    ),
+                    &draw_state::DrawState::default(),
+                    c.transform,
+                    gl,
+                );
+            }
+        });
+        if let Some(Button::Mouse(MouseButton::Right)) = e.press_args() {
+            window.set_should_close(true); // doesn't seem to be working
+            window.hide();
+            return Ok(None);
+        }
+        if let Some(Button::Mouse(MouseButton::Left)) = e.press_args() {
+            is_mouse_down = true;
+        }
+        if is_mouse_down {
+            if start_pos == (0.0, 0.0) {
+                e.mouse_cursor(|[x, y]| {
+                    start_pos = (x, y);
+                    d!(eprintln!("start position {}, {}", x, y));
+                });
+            }
+            if let Some(ending) = e.release(|button| {
+                if button == Button::Mouse(MouseButton::Left) {
+                    is_mouse_down = false;
+                    if last_pos.0 > start_pos.0 && last_pos.1 > start_pos.1 {
+                        if !is_too_small(start_pos, last_pos, dpi_factor, min_size) {
+                            return true;
+                        }
+                        let (min_width, min_height) = min_size.unwrap();
+                        complain!(
+                            "Selection is smaller than the --min-size of {}x{}, drag a larger one",
+                            min_width, min_height
+                        );
+                        start_pos = (0.0, 0.0);
+                        last_pos = (0.0, 0.0);
+                    } else {
+                        start_pos = (0.0, 0.0);
+                        last_pos = (0.0, 0.0);
+                    }
+                }
+                false
+            }) {
+                if ending {
+                    window.set_should_close(true); // doesn't seem to be working
+                    window.hide();
+                    return Ok(Some(Rect {
+                        top_left: (
+                            (start_pos.0 * dpi_factor) as u32,
+                            (start_pos.1 * dpi_factor) as u32,
+                        ),
+                        bottom_right: (
+                            (last_pos.0 * dpi_factor) as u32,
+                            (last_pos.1 * dpi_factor) as u32,
+                        ),
+                    }));
+                } else {
+                    continue;
+                }
+            }
+            e.mouse_cursor(|[x, y]| {
+                last_pos = (x.max(0.0), y.max(0.0));
+            });
+        }
+    }
+    Ok(None)
+}
+
+/// Lays the screenshot over the desktop in a borderless window, returning it along with the
+/// texture to draw and the dpi factor between window and screenshot pixels.
+fn open_screenshot_window(
+    screenshot: &PresentabeScreenshot,
+) -> Result<(PistonWindow, G2dTexture, f64), Error> {
+    let draw_width = screenshot.image.width();
+    let draw_height = screenshot.image.height() - 1; // if we're perfectly matching on Windows, it'll become a 'fullscreen app' that takes seconds to load
+    let mut window: PistonWindow = WindowSettings::new("NCScreenier", [draw_width, draw_height])
+        .exit_on_esc(true)
+        .decorated(false)
+        .resizable(false)
+        .fullscreen(false)
+        .vsync(true)
+        .build()
+        .map_err(|e| Error::Window(e.to_string()))?;
+    window.set_position(piston_window::Position {
+        x: screenshot.x,
+        y: screenshot.y,
+    });
+    window.set_lazy(true);
+
+    // window.window.window.set_always_on_top(true); // not in latest version of piston_window >.>
+    let dpi_factor = EventsLoop::new().get_primary_monitor().get_hidpi_factor();
+    if dpi_factor != 1.0 {
+        d!(eprintln!("dpi factor {:?}", dpi_factor));
+        window.set_size([
+            (draw_width as f64 / dpi_factor) as u32,
+            (draw_height as f64 / dpi_factor) as u32,
+        ]);
+    }
+
+    let screenshot_texture: G2dTexture = if dpi_factor == 1.0 {
+        Texture::from_image(
+            &mut window.create_texture_context(),
+            &screenshot.image,
+            &TextureSettings::new(),
+        )
+        .map_err(|e| Error::Window(format!("couldn't create texture: {:?}", e)))?
+    } else {
+        Texture::from_image(
+            &mut window.create_texture_context(),
+            &image::imageops::resize(
+                &screenshot.image,
+                (draw_width as f64 / dpi_factor) as u32,
+                (screenshot.image.height() as f64 / dpi_factor) as u32,
+                FilterType::Lanczos3,
+            ),
+            &TextureSettings::new(),
+        )
+        .map_err(|e| Error::Window(format!("couldn't create texture: {:?}", e)))?
+    };
+
+    Ok((window, screenshot_texture, dpi_factor))
+}
+
+/// Shows the screenshot like `present_for_cropping`, returning the colour of the pixel clicked,
+/// or `Ok(None)` if right clicked away.
+pub fn pick_color(screenshot: &PresentabeScreenshot) -> Result<Option<image::Rgb<u8>>, Error> {
+    let (mut window, screenshot_texture, dpi_factor) = open_screenshot_window(screenshot)?;
+    let mut hovered_pos: Option<(f64, f64)> = None;
+    let pixel_at = |(x, y): (f64, f64)| {
+        let x = ((x * dpi_factor) as u32).min(screenshot.image.width() - 1);
+        let y = ((y * dpi_factor) as u32).min(screenshot.image.height() - 1);
+        let pixel = screenshot.image.get_pixel(x, y);
+        image::Rgb([pixel[0], pixel[1], pixel[2]])
+    };
+
+    while let Some(e) = window.next() {
+        let e: piston_window::Event = e;
+        e.mouse_cursor(|[x, y]| {
+            hovered_pos = Some((x.max(0.0), y.max(0.0)));
+        });
+
+        window.draw_2d(&e, |c, gl, _device| {
+            image(&screenshot_texture, c.transform, gl);
+            if let Some(pos) = hovered_pos {
+                let pixel = pixel_at(pos);
+                let swatch = [
+                    pos.0 + SWATCH_OFFSET,
+                    pos.1 + SWATCH_OFFSET,
+                    SWATCH_SIZE,
+                    SWATCH_SIZE,
+                ];
+                rectangle(
+                    [
+                        pixel[0] as f32 / 255.0,
+                        pixel[1] as f32 / 255.0,
+                        pixel[2] as f32 / 255.0,
+                        1.0,
+                    ],
+                    swatch,
+                    c.transform,
+                    gl,
+                );
+                rectangle::Rectangle::new_border(SELECTION_COLOUR, 1.0).draw(
+                    swatch,
+                    &draw_state::DrawState::default(),
+                    c.transform,
+                    gl,
+                );
+
+                let label = to_hex(pixel);
+                let label_pos = (
+                    swatch[0] + SWATCH_SIZE + GLYPH_DOT * 2.0,
+                    swatch[1] + (SWATCH_SIZE - 5.0 * GLYPH_DOT) / 2.0,
+                );
+                rectangle(
+                    [0.0, 0.0, 0.0, 0.7],
+                    [
+                        label_pos.0 - GLYPH_DOT,
+                        label_pos.1 - GLYPH_DOT,
+                        (label.len() * 4 + 1) as f64 * GLYPH_DOT,
+                        7.0 * GLYPH_DOT,
+                    ],
+                    c.transform,
+                    gl,
+                );
+                draw_hex_text(&label, label_pos, c.transform, gl);
+            }
+        });
+        match e.press_args() {
+            Some(Button::Mouse(MouseButton::Right)) => {
+                window.set_should_close(true);
+                window.hide();
+                return Ok(None);
+            }
+            Some(Button::Mouse(MouseButton::Left)) => {
+                if let Some(pos) = hovered_pos {
+                    window.set_should_close(true);
+                    window.hide();
+                    return Ok(Some(pixel_at(pos)));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+/// 3x5 bitmaps for the characters `to_hex` produces, one row per byte with the leftmost dot
+/// in the highest bit. Drawing them as rectangles saves shipping a font just for the colour label.
+fn hex_glyph(c: char) -> [u8; 5] {
+    match c {
+        '#' => [0b101, 0b111, 0b101, 0b111, 0b101],
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        _ => [0; 5],
+    }
+}
+
+/// Draws `text` in white `hex_glyph`s, with its top left corner at `(x, y)`.
+fn draw_hex_text(text: &str, (x, y): (f64, f64), transform: math::Matrix2d, gl: &mut G2d) {
+    for (i, c) in text.chars().enumerate() {
+        let glyph_x = x + (i * 4) as f64 * GLYPH_DOT;
+        for (row, bits) in hex_glyph(c).iter().enumerate() {
+            for column in 0..3 {
+                if bits & (0b100 >> column) != 0 {
+                    rectangle(
+                        [1.0, 1.0, 1.0, 1.0],
+                        [
+                            glyph_x + column as f64 * GLYPH_DOT,
+                            y + row as f64 * GLYPH_DOT,
+                            GLYPH_DOT,
+                            GLYPH_DOT,
+                        ],
+                        transform,
+                        gl,
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Formats a colour as `#RRGGBB`.
+pub fn to_hex(color: image::Rgb<u8>) -> String {
+    format!("#{:02X}{:02X}{:02X}", color[0], color[1], color[2])
+}
+
+fn is_too_small(
+    start_pos: (f64, f64),
+    last_pos: (f64, f64),
+    dpi_factor: f64,
+    min_size: Option<(u32, u32)>,
+) -> bool {
+    match min_size {
+        Some((min_width, min_height)) => {
+            (((last_pos.0 - start_pos.0) * dpi_factor) as u32) < min_width
+                || (((last_pos.1 - start_pos.1) * dpi_factor) as u32) < min_height
+        }
+        None => false,
+    }
+}
+
+struct CapturerPosition {
+    capturer: Capturer,
+    top: i32,
+    left: i32,
+    right: i32,
+    bottom: i32,
+}
+
+/// Calls `attempt` up to `attempts` times, sleeping `pause` between failures.
+fn retry<T, E: fmt::Display>(
+    attempts: u32,
+    pause: Duration,
+    mut attempt: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    let mut result = attempt();
+    for attempt_number in 1..attempts {
+        match &result {
+            Ok(_) => break,
+            Err(e) => d!(eprintln!("attempt {} failed: {}", attempt_number, e)),
+        }
+        thread::sleep(pause);
+        result = attempt();
+    }
+    result
+}
+
+// Capturer::new can fail briefly after a resolution change, or while DXGI is busy
+fn start_capturer(index: usize, display: Display) -> io::Result<Capturer> {
+    let mut display = Some(display);
+    retry(CAPTURER_ATTEMPTS, *CAPTURER_RETRY_PAUSE, || {
+        let display = match display.take() {
+            Some(display) => display,
+            // a failed Capturer::new consumes its Display, so look it up again
+            None => Display::all()?.into_iter().nth(index).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "display disappeared")
+            })?,
+        };
+        Capturer::new(display)
+    })
+}
+
+struct SubImage {
+    image: Option<image::RgbaImage>,
+    top: i32,
+    left: i32,
+    w: u32,
+    h: u32,
+}
+
+pub struct PresentabeScreenshot {
+    pub image: image::RgbaImage,
+    /// Further animation frames, recorded while Shift was held (and Ctrl wasn't)
+    pub additional_images: Vec<RgbaImage>,
+    /// Milliseconds each frame is shown for
+    pub delays: Vec<u16>,
+    /// Desktop position of the image's top left corner
+    pub x: i32,
+    pub y: i32,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct DisplayBounds {
+    left: i32,
+    top: i32,
+    right: i32,
+    bottom: i32,
+}
+
+impl DisplayBounds {
+    fn contains(&self, other: &DisplayBounds) -> bool {
+        self.left <= other.left
+            && self.top <= other.top
+            && self.right >= other.right
+            && self.bottom >= other.bottom
+    }
+}
+
+/// Which displays are worth capturing: mirrored displays report the same (or a contained) region,
+/// so capturing them again would just overwrite identical pixels. Identical regions keep the first.
+fn displays_to_capture(bounds: &[DisplayBounds]) -> Vec<bool> {
+    bounds
+        .iter()
+        .enumerate()
+        .map(|(i, display)| {
+            !bounds.iter().enumerate().any(|(j, other)| {
+                i != j && other.contains(display) && (other != display || j < i)
+            })
+        })
+        .collect()
+}
+
+/// Captures all displays into one image, continuing to record frames while Shift is held.
+/// The cursor isn't in them: scrap's dxgi (Windows) and x11 backends leave the hardware cursor
+/// out of captures. The quartz (macOS) backend hasn't been checked.
+pub fn capture_screenshot(options: &CaptureOptions) -> Result<PresentabeScreenshot, Error> {
+    let desktop = start_capture()?;
+    let mut additional_images: Vec<RgbaImage> = Vec::new();
+    let (image, delays) = record(&desktop, options, &mut additional_images)?;
+    Ok(PresentabeScreenshot {
+        image,
+        additional_images,
+        delays,
+        x: desktop.min_x,
+        y: desktop.min_y,
+    })
+}
+
+/// Every capturable display, and the bounds of the desktop they make up.
+struct Desktop {
+    capturers: Vec<RefCell<CapturerPosition>>,
+    min_x: i32,
+    min_y: i32,
+    max_x: i32,
+    max_y: i32,
+}
+
+impl Desktop {
+    fn capture(&self, base_image: Option<&RgbaImage>) -> Result<RgbaImage, Error> {
+        capture_image(
+            &self.capturers,
+            self.min_x,
+            self.min_y,
+            self.max_x,
+            self.max_y,
+            base_image,
+        )
+    }
+}
+
+fn start_capture() -> Result<Desktop, Error> {
+    let all_displays: Vec<Display> = Display::all().map_err(Error::Capture)?;
+    if all_displays.is_empty() {
+        return Err(Error::Capture(io::Error::new(io::ErrorKind::NotFound, "no displays found")));
+    }
+    // Start every display first and only then drop the redundant ones, so a mirror can stand in
+    // for a display whose capture couldn't begin
+    let started: Vec<CapturerPosition> = all_displays
+        .into_iter()
+        .enumerate()
+        .filter_map(|(index, display)| {
+            let (left, top, right, bottom) =
+                (display.left(), display.top(), display.right(), display.bottom());
+            match start_capturer(index, display) {
+                Ok(capturer) => Some(CapturerPosition {
+                    capturer,
+                    top,
+                    left,
+                    right,
+                    bottom,
+                }),
+                Err(e) => {
+                    complain!("Skipping display at {},{}, couldn't begin capture: {}", left, top, e);
+                    None
+                }
+            }
+        })
+        .collect();
+    let keep = displays_to_capture(
+        &started
+            .iter()
+            .map(|position| DisplayBounds {
+                left: position.left,
+                top: position.top,
+                right: position.right,
+                bottom: position.bottom,
+            })
+            .collect::<Vec<_>>(),
+    );
+    let capturers: Vec<RefCell<CapturerPosition>> = started
+        .into_iter()
+        .zip(keep.into_iter())
+        .filter_map(|(position, keep)| {
+            if !keep {
+                d!(eprintln!("skipping redundant display at {},{}", position.left, position.top));
+                return None;
+            }
+            Some(RefCell::new(position))
+        })
+        .collect();
+    if capturers.is_empty() {
+        return Err(Error::Capture(io::Error::new(
+            io::ErrorKind::Other,
+            "couldn't begin capture on any display",
+        )));
+    }
+    let max_x = capturers.iter().map(|c| c.borrow().right).max().unwrap();
+    let min_x = capturers.iter().map(|c| c.borrow().left).min().unwrap();
+    let max_y = capturers.iter().map(|c| c.borrow().bottom).max().unwrap();
+    let min_y = capturers.iter().map(|c| c.borrow().top).min().unwrap();
+    d!(eprintln!(
+        "Capturing screenshot with dimensions: {},{} {},{}",
+        min_x, min_y, max_x, max_y
+    ));
+
+    Ok(Desktop {
+        capturers,
+        min_x,
+        min_y,
+        max_x,
+        max_y,
+    })
+}
+
+/// Captures the first frame, then feeds `sink` further frames for as long as Shift is held.
+/// Returns the first frame, and every frame's delay.
+fn record<S: FrameSink>(
+    desktop: &Desktop,
+    options: &CaptureOptions,
+    sink: &mut S,
+) -> Result<(RgbaImage, Vec<u16>), Error> {
+    let frame_interval = options
+        .fps
+        .filter(|&fps| fps > 0)
+        .map(|fps| Duration::new(1, 0) / fps);
+
+    let mut prev_frame_time = SystemTime::now();
+    let big_image = desktop.capture(None)?;
+
+    // Under --fps the first frame is shown for one interval like the rest, however long it took
+    let first_delay = frame_interval.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(prev_frame_time)
+            .unwrap_or_default()
+    });
+    let mut delays: Vec<u16> = vec![first_delay.as_millis() as u16];
+    prev_frame_time = SystemTime::now();
+    let device_state = DeviceState::new();
+    loop {
+        let keys = device_state.get_keys();
+        if !keys
+            .iter()
+            .any(|key| *key == Keycode::LShift || *key == Keycode::RShift)
+        {
+            break;
+        }
+        if keys
+            .iter()
+            .any(|key| *key == Keycode::LControl || *key == Keycode::RControl)
+        {
+            // Paused: keep the clock moving so the pause doesn't end up in the next frame's delay
+            thread::sleep(*ONE_FRAME);
+            prev_frame_time = SystemTime::now();
+            continue;
+        }
+        d!(print_time("Before additional image"));
+        let frame = desktop.capture(Some(sink.last().unwrap_or(&big_image)))?;
+        sink.push(frame)?;
+        if let Some(frame_interval) = frame_interval {
+            let elapsed = SystemTime::now()
+                .duration_since(prev_frame_time)
+                .unwrap_or_default();
+            if elapsed < frame_interval {
+                thread::sleep(frame_interval - elapsed);
+            }
+        }
+        delays.push(
+            SystemTime::now()
+                .duration_since(prev_frame_time)
+                .unwrap_or_default()
+                .as_millis() as u16,
+        );
+        prev_frame_time = SystemTime::now();
+    }
+
+    Ok((big_image, delays))
+}
+
+fn capture_image(
+    capturers: &Vec<RefCell<CapturerPosition>>,
+    min_x: i32,
+    min_y: i32,
+    max_x: i32,
+    max_y: i32,
+    base_image: Option<&RgbaImage>,
+) -> Result<RgbaImage, Error> {
+    let mut big_image = image::RgbaImage::new((max_x - min_x) as u32, (max_y - min_y) as u32);
+    d!(print_time("initialized image"));
+
+    let subimages = capturers
+        .iter()
+        .map(|capturer_position_cell| {
+            let mut capturer_position = capturer_position_cell.borrow_mut();
+            let w = capturer_position.capturer.width();
+            let h = capturer_position.capturer.height();
+            let mut frames_asleep = 0;
+            loop {
+                match capturer_position.capturer.frame() {
+                    Ok(captured_buffer) => {
+                        if !captured_buffer.to_vec().iter().any(|&x| x != 0) {
+                            // sometimes it captures all black?? skip
+                            d!(eprintln!("black frame"));
+                            thread::sleep(*DURATION_1MS);
+                            continue;
+                        }
+                        return Ok(SubImage {
+                            image: Some(scrap_buffer_to_rgbaimage(w, h, captured_buffer)),
+                            top: capturer_position.top,
+                            left: capturer_position.left,
+                            w: w as u32,
+                            h: h as u32,
+                        });
+                    }
+                    Err(error) => {
+                        if error.kind() == WouldBlock {
+                            if frames_asleep > 20 && base_image.is_some() {
+                                return Ok(SubImage {
+                                    image: None,
+                                    top: capturer_position.top,
+                                    left: capturer_position.left,
+                                    w: w as u32,
+                                    h: h as u32,
+                                });
+                            }
+                            // Wait until there's a frame.
+                            d!(eprintln!("would block {:?}", frames_asleep));
+                            frames_asleep += 1;
+                            //thread::sleep(*DURATION_1MS);
+                            continue;
+                        } else {
+                            return Err(Error::Capture(error));
+                        }
+                    }
+                };
+            }
+        })
+        .collect::<Result<Vec<SubImage>, Error>>()?;
+    let (canvas_width, canvas_height) = big_image.dimensions();
+    for subimage in subimages {
+        let (offset, position, (w, h)) = match visible_part(
+            subimage.left - min_x,
+            subimage.top - min_y,
+            subimage.w,
+            subimage.h,
+            canvas_width,
+            canvas_height,
+        ) {
+            Some(visible) => visible,
+            None => continue,
+        };
+        let copied = match subimage.image {
+            None => big_image.copy_from(
+                &base_image.unwrap().view(position.0, position.1, w, h),
+                position.0,
+                position.1,
+            ),
+            Some(image) => big_image.copy_from(
+                &image.view(offset.0, offset.1, w, h),
+                position.0,
+                position.1,
+            ),
+        };
+        copied.map_err(|e| Error::Encode(format!("error compositing displays: {}", e)))?;
+    }
+    Ok(big_image)
+}
+
+/// The part of a `w`x`h` region placed at (`left`, `top`) that lands on the canvas, as
+/// (offset into the region, position on the canvas, size), or None if it's entirely off canvas.
+fn visible_part(
+    left: i32,
+    top: i32,
+    w: u32,
+    h: u32,
+    canvas_width: u32,
+    canvas_height: u32,
+) -> Option<((u32, u32), (u32, u32), (u32, u32))> {
+    let clamp = |start: i32, length: u32, canvas_length: u32| {
+        let first = start.max(0) as i64;
+        let last = (start as i64 + length as i64).min(canvas_length as i64);
+        if last <= first {
+            None
+        } else {
+            Some(((first - start as i64) as u32, first as u32, (last - first) as u32))
+        }
+    };
+    let (offset_x, x, visible_w) = clamp(left, w, canvas_width)?;
+    let (offset_y, y, visible_h) = clamp(top, h, canvas_height)?;
+    Some(((offset_x, offset_y), (x, y), (visible_w, visible_h)))
+}
+
+fn scrap_buffer_to_rgbaimage(w: usize, h: usize, buffer: scrap::Frame) -> image::RgbaImage {
+    // Flip the ARGB image into a BGRA image.
+    let mut bitflipped = Vec::with_capacity(w * h * 4);
+    let stride = buffer.len() / h;
+    for y in 0..h {
+        for x in 0..w {
+            let i = stride * y + 4 * x;
+            bitflipped.extend_from_slice(&[buffer[i + 2], buffer[i + 1], buffer[i], 255]);
+        }
+    }
+    image::RgbaImage::from_raw(w as u32, h as u32, bitflipped).unwrap()
+}
+
+fn print_time(s: &str) {
+    eprintln!(
+        "{:<20}: {:?}",
+        s,
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An attempt that fails its first `failures` calls, counting every call in `calls`.
+    fn flaky(failures: u32, calls: &mut u32) -> impl FnMut() -> Result<u32, String> + '_ {
+        move || {
+            *calls += 1;
+            if *calls <= failures {
+                Err(format!("failure {}", calls))
+            } else {
+                Ok(*calls)
+            }
+        }
+    }
+
+    #[test]
+    fn retry_succeeds_after_fewer_failures_than_attempts() {
+        let mut calls = 0;
+        let result = retry(5, Duration::new(0, 0), flaky(3, &mut calls));
+        assert_eq!(result, Ok(4));
+        assert_eq!(calls, 4);
+    }
+
+    #[test]
+    fn retry_stops_at_the_first_success() {
+        let mut calls = 0;
+        assert_eq!(retry(5, Duration::new(0, 0), flaky(0, &mut calls)), Ok(1));
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn retry_returns_the_last_error_once_out_of_attempts() {
+        let mut calls = 0;
+        let result = retry(3, Duration::new(0, 0), flaky(3, &mut calls));
+        assert_eq!(result, Err("failure 3".to_string()));
+        assert_eq!(calls, 3);
+
+        let mut calls = 0;
+        let result = retry(3, Duration::new(0, 0), flaky(10, &mut calls));
+        assert_eq!(result, Err("failure 3".to_string()));
+        assert_eq!(calls, 3);
+    }
+
+    /// `record` only talks to its sink through `FrameSink`, so this covers what recording does
+    /// with a spool, short of capturing the desktop.
+    #[test]
+    fn frame_spool_writes_cropped_frames_to_disk_and_reads_them_back() {
+        let rect = Rect {
+            top_left: (2, 1),
+            bottom_right: (6, 4),
+        };
+        let frame =
+            |i: u8| RgbaImage::from_fn(8, 6, |x, y| image::Rgba([i, x as u8, y as u8, 255]));
+        let mut spool = FrameSpool::create(rect, 1).unwrap();
+        for i in 0..50 {
+            spool.push(frame(i)).unwrap();
+        }
+        assert_eq!(spool.frames, 50);
+        // every cropped frame has gone to the file; the only one still held is the newest,
+        // uncropped, for filling in displays that haven't produced a new frame
+        spool.writer.flush().unwrap();
+        let path = spool.file.0.clone();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 50 * 4 * 3 * 3);
+        assert_eq!(FrameSink::last(&spool), Some(&frame(49)));
+
+        let frames: Vec<RgbImage> = spool.into_frames().unwrap().map(Result::unwrap).collect();
+        assert_eq!(frames.len(), 50);
+        for (i, spooled) in frames.iter().enumerate() {
+            assert_eq!(spooled, &crop_to_rgb(&frame(i as u8), &rect, 1));
+        }
+        assert!(!path.exists(), "the spool file is removed once read back");
+    }
+
+    #[test]
+    fn frame_spools_get_unique_files() {
+        let rect = Rect {
+            top_left: (0, 0),
+            bottom_right: (1, 1),
+        };
+        let first = FrameSpool::create(rect, 1).unwrap();
+        let second = FrameSpool::create(rect, 1).unwrap();
+        assert_ne!(first.file.0, second.file.0);
+    }
+
+    fn region(x: i32, y: i32, width: u32, height: u32) -> Selection {
+        Selection::Region {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn fixed_selection_translates_and_clamps_regions() {
+        // desktop starting at -1920,0, so desktop x 0 is image x 1920
+        let rect = fixed_selection(region(-10, 100, 50, 20), -1920, 0, 4480, 1440)
+            .unwrap()
+            .unwrap();
+        assert_eq!((rect.top_left, rect.bottom_right), ((1910, 100), (1960, 120)));
+
+        let rect = fixed_selection(region(4000, 1400, 1000, 1000), 0, 0, 4480, 1440)
+            .unwrap()
+            .unwrap();
+        assert_eq!((rect.top_left, rect.bottom_right), ((4000, 1400), (4480, 1440)));
+    }
+
+    #[test]
+    fn fixed_selection_rejects_regions_off_the_desktop_without_overflowing() {
+        assert!(fixed_selection(region(i32::MAX, 0, 100, 100), 0, 0, 1920, 1080).is_err());
+        assert!(fixed_selection(region(i32::MIN, 0, u32::MAX, 100), 1, 0, 1920, 1080).is_ok());
+        assert!(fixed_selection(region(0, 2000, 100, 100), 0, 0, 1920, 1080).is_err());
+    }
+
+    fn test_directory(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("ncscreenier-test-{}-{}", std::process::id(), name))
+    }
+
+    fn desktop_frame(i: u8) -> RgbaImage {
+        RgbaImage::from_fn(64, 48, |x, y| image::Rgba([x as u8, y as u8, i, 255]))
+    }
+
+    /// Runs `--no-watch --full --no-upload`'s path from just after the desktop is captured,
+    /// with `frames` recorded after the first.
+    fn save_full_recording(frames: u8, directory: &Path) -> SavedScreenshot {
+        let rect = fixed_selection(Selection::Full, -64, 0, 64, 48).unwrap().unwrap();
+        assert_eq!((rect.top_left, rect.bottom_right), ((0, 0), (64, 48)));
+        let options = SaveOptions::default();
+        let mut spool = FrameSpool::create(rect, options.scale_for(&rect)).unwrap();
+        for i in 1..=frames {
+            spool.push(desktop_frame(i)).unwrap();
+        }
+        let delays = vec![16; 1 + frames as usize];
+        save_recording(&desktop_frame(0), delays, spool, directory, &options).unwrap()
+    }
+
+    #[test]
+    fn full_capture_saves_a_png_without_a_window() {
+        let directory = test_directory("png");
+        let saved = save_full_recording(0, &directory);
+        assert_eq!((saved.width, saved.height, saved.frames), (64, 48, 1));
+        let reloaded = image::open(&saved.filepath).unwrap();
+        assert_eq!(reloaded.dimensions(), (64, 48));
+        assert_eq!(reloaded.to_rgba().get_pixel(10, 20), &image::Rgba([10, 20, 0, 255]));
+        std::fs::remove_dir_all(&directory).ok();
+    }
+
+    #[test]
+    fn full_capture_saves_a_recording_without_a_window() {
+        let directory = test_directory("apng");
+        let saved = save_full_recording(3, &directory);
+        assert_eq!((saved.width, saved.height, saved.frames), (64, 48, 4));
+        assert!(saved.bytes > 0);
+        // viewers without APNG support see the first frame
+        let reloaded = image::open(&saved.filepath).unwrap();
+        assert_eq!(reloaded.dimensions(), (64, 48));
+        assert_eq!(reloaded.to_rgba().get_pixel(10, 20), &image::Rgba([10, 20, 0, 255]));
+        std::fs::remove_dir_all(&directory).ok();
+    }
+
+    fn bounds(left: i32, top: i32, width: i32, height: i32) -> DisplayBounds {
+        DisplayBounds {
+            left,
+            top,
+            right: left + width,
+            bottom: top + height,
+        }
+    }
+
+    #[test]
+    fn identical_displays_keep_only_the_first() {
+        let mirrored = bounds(0, 0, 1920, 1080);
+        assert_eq!(displays_to_capture(&[mirrored, mirrored]), vec![true, false]);
+        assert_eq!(
+            displays_to_capture(&[mirrored, mirrored, mirrored]),
+            vec![true, false, false]
+        );
+    }
+
+    #[test]
+    fn contained_displays_are_skipped() {
+        let big = bounds(0, 0, 2560, 1440);
+        let inside = bounds(0, 0, 1920, 1080);
+        assert_eq!(displays_to_capture(&[inside, big]), vec![false, true]);
+        assert_eq!(displays_to_capture(&[big, inside]), vec![true, false]);
+    }
+
+    #[test]
+    fn overlapping_and_disjoint_displays_are_all_kept() {
+        let left = bounds(0, 0, 1920, 1080);
+        let overlapping = bounds(1000, 500, 1920, 1080);
+        let disjoint = bounds(-1920, 0, 1920, 1080);
+        assert_eq!(displays_to_capture(&[left, overlapping]), vec![true, true]);
+        assert_eq!(displays_to_capture(&[left, disjoint]), vec![true, true]);
+    }
+
+    #[test]
+    fn visible_part_places_displays_left_of_the_primary() {
+        // a 1920x1080 display at -1920,0 beside a 2560x1440 primary, on a 4480x1440 canvas
+        let (min_x, min_y) = (-1920, 0);
+        assert_eq!(
+            visible_part(-1920 - min_x, 0 - min_y, 1920, 1080, 4480, 1440),
+            Some(((0, 0), (0, 0), (1920, 1080)))
+        );
+        assert_eq!(
+            visible_part(0 - min_x, 0 - min_y, 2560, 1440, 4480, 1440),
+            Some(((0, 0), (1920, 0), (2560, 1440)))
+        );
+    }
+
+    #[test]
+    fn visible_part_clips_regions_hanging_off_the_canvas() {
+        assert_eq!(
+            visible_part(4000, 1000, 1000, 1000, 4480, 1440),
+            Some(((0, 0), (4000, 1000), (480, 440)))
+        );
+        assert_eq!(
+            visible_part(-100, -50, 300, 200, 4480, 1440),
+            Some(((100, 50), (0, 0), (200, 150)))
+        );
+        assert_eq!(visible_part(4480, 0, 100, 100, 4480, 1440), None);
+        assert_eq!(visible_part(-100, 0, 100, 100, 4480, 1440), None);
+    }
+
+    fn upscaled_square(side: u32) -> u32 {
+        let options = SaveOptions {
+            upscale_small_crops: true,
+            ..SaveOptions::default()
+        };
+        options.scale_for(&Rect {
+            top_left: (10, 10),
+            bottom_right: (10 + side, 10 + side),
+        })
+    }
+
+    #[test]
+    fn scale_for_brings_small_crops_up_to_the_threshold() {
+        assert_eq!(upscaled_square(1), 128);
+        assert_eq!(upscaled_square(100), 2);
+        assert_eq!(upscaled_square(127), 2);
+        assert_eq!(upscaled_square(128), 1);
+        assert_eq!(upscaled_square(500), 1);
+        let wide = Rect {
+            top_left: (0, 0),
+            bottom_right: (100, 1),
+        };
+        assert_eq!(SaveOptions::default().scale_for(&wide), 1);
+    }
+
+    #[test]
+    fn is_too_small_measures_in_screenshot_pixels() {
+        let min_size = Some((16, 16));
+        assert!(is_too_small((0.0, 0.0), (15.0, 16.0), 1.0, min_size));
+        assert!(!is_too_small((0.0, 0.0), (16.0, 16.0), 1.0, min_size));
+        assert!(is_too_small((10.0, 10.0), (17.5, 18.0), 2.0, min_size));
+        assert!(!is_too_small((10.0, 10.0), (18.0, 18.0), 2.0, min_size));
+        assert!(!is_too_small((0.0, 0.0), (1.0, 1.0), 1.0, None));
+    }
+}
@@ -1,18 +1,11 @@
-extern crate apng_encoder;
-extern crate chrono;
 extern crate clipboard;
 extern crate ctrlc;
-extern crate device_query;
 extern crate docopt;
-extern crate image;
 extern crate livesplit_hotkey;
-extern crate oxipng;
-extern crate piston_window;
-extern crate reqwest;
-extern crate scrap;
-extern crate winit;
+extern crate open;
+extern crate ncscreenier;
 #[macro_use]
-extern crate lazy_static;
+extern crate serde_json;
 
 #[cfg(windows)]
 extern crate kernel32;
@@ -21,48 +14,34 @@ extern crate user32;
 #[cfg(windows)]
 extern crate winapi;
 
-use apng_encoder::{Color, Delay, Encoder, Frame, Meta};
 use clipboard::ClipboardContext;
 use clipboard::ClipboardProvider;
-use core::borrow::BorrowMut;
-use device_query::{DeviceQuery, DeviceState, Keycode};
-use image::png::PNGEncoder;
-use image::{
-    ColorType, GenericImage, GenericImageView, RgbImage, RgbaImage,
-};
-use image::imageops::FilterType;
-use image::buffer::ConvertBuffer;
 use livesplit_hotkey::KeyCode;
-use piston_window::*;
-use scrap::{Capturer, Display};
-use std::cell::RefCell;
-use std::cmp::max;
-use std::fs::File;
-use std::io::stdout;
-use std::io::ErrorKind::WouldBlock;
-use std::io::Write;
+use ncscreenier::CopyFormat;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use winit::{EventsLoop};
 
-const SELECTION_COLOUR: [f32; 4] = [0.0, 0.0, 1.0, 1.0];
 const VERSION: &'static str = env!("CARGO_PKG_VERSION");
-lazy_static! {
-    static ref ONE_FRAME: Duration = Duration::new(1, 0) / 60;
-    static ref DURATION_1MS: Duration = Duration::new(0, 1);
-}
+const DEFAULT_CONFIG: &'static str = "ncscreenier.toml";
 
-#[cfg(debug_assertions)]
-const DEBUGGING: bool = true;
-#[cfg(not(debug_assertions))]
-const DEBUGGING: bool = false;
+// Progress output, silenced under --json along with the library's
+macro_rules! sayln {
+    ($($arg:tt)*) => {
+      if ncscreenier::human_output() {
+        println!($($arg)*);
+      }
+    };
+}
 
-macro_rules! d {
+// Errors still get reported under --json, just on stderr instead
+macro_rules! complain {
     ($($arg:tt)*) => {
-      if DEBUGGING {
-        ($($arg)*);
+      if ncscreenier::human_output() {
+        println!($($arg)*);
+      } else {
+        eprintln!($($arg)*);
       }
     };
 }
@@ -78,8 +57,9 @@ fn main() {
 NCScreenie {} - Screenshot Cropper & Uploader
 
 Usage:
-    ncscreenier [--watch] [--directory=<DIR>] [--account=<name>] [--quiet]
-    ncscreenier [--no-watch] [--directory=<DIR>] [--account=<name>]
+    ncscreenier [--watch] [options] [--quiet]
+    ncscreenier [--no-watch] [options]
+    ncscreenier --delete-last [options]
     ncscreenier [--help]
 
 Options:
@@ -87,39 +67,243 @@ Options:
     --account=<name>  Account to upload under [default: anon]
     --watch           Watch for printscreens (default)
     --no-watch        Disable watching for printscreen, just immediately capture once
-    --directory=DIR   Output directory for screenshots [default: ./]
+    --directory=DIR   Output directory for screenshots, defaults to the account's or ./
+    --config=<file>   Config file defining accounts [default: ncscreenier.toml]
     --quiet           (Windows only) hide the cmd window
+    --json            Print a JSON result line per capture instead of progress messages
+    --copy-format=<fmt>  Clipboard link style: plain, markdown, bbcode or html [default: plain]
+    --no-early-copy   Don't copy the pending link (suffixed with '?') while the upload is in progress
+    --delete-last     Delete the most recent upload from its server and from disk
+    --loops=<n>       How many times recordings play, 0 loops forever [default: 0]
+    --fallback-last   Show a recording's last frame, rather than its first, in viewers without APNG support
+    --fps=<n>         Limit recordings to at most n frames per second
+    --min-size=<WxH>  Refuse crop selections smaller than this, eg. 16x16
+    --upscale-small-crops  Enlarge tiny crops with nearest neighbour scaling so they stay legible
+    --region=<rect>   Crop to this desktop region (x,y,w,h) instead of showing the crop window
+    --full            Keep the whole desktop instead of showing the crop window
+    --no-upload       Only save the screenshot locally
+    --pick-color      Click a pixel to copy its #RRGGBB colour, instead of cropping
+    --strip-exif      Strip all non-essential metadata chunks from saved pngs
+    --open            Open the uploaded link (or saved file, with --no-upload) after each capture
+    --full-screen-key=<key>  Also watch this key (Pause, ScrollLock, Insert or F9-F12) to capture the whole desktop uncropped
     ",
         VERSION
     ))
     .and_then(|dopt| dopt.parse())
     .unwrap_or_else(|e| e.exit());
 
-    let directory = cli_args.get_str("--directory").to_string();
-    let account = cli_args.get_str("--account").to_string();
+    let config_path = Path::new(cli_args.get_str("--config"));
+    // a missing default config is fine, anon uploads to nebtown need no setup
+    let config = if config_path.exists() {
+        ncscreenier::Config::load(config_path)
+            .unwrap_or_else(|e| docopt::Error::Argv(e.to_string()).exit())
+    } else if cli_args.get_str("--config") != DEFAULT_CONFIG {
+        docopt::Error::Argv(format!("Config file {} not found", config_path.display())).exit()
+    } else {
+        ncscreenier::Config::default()
+    };
+    let last_upload_path = ncscreenier::last_upload_path(config_path);
+    let account_name = cli_args.get_str("--account").to_string();
+    let account = config
+        .account(&account_name)
+        .unwrap_or_else(|e| docopt::Error::Argv(e.to_string()).exit());
+    let directory = match cli_args.get_str("--directory") {
+        "" => account.directory.clone().unwrap_or_else(|| PathBuf::from("./")),
+        directory => PathBuf::from(directory),
+    };
+    let json_output = cli_args.get_bool("--json");
+    if json_output {
+        ncscreenier::set_human_output(false);
+    }
+    let copy_format: CopyFormat = cli_args
+        .get_str("--copy-format")
+        .parse()
+        .unwrap_or_else(|e: String| docopt::Error::Argv(e).exit());
+    let pick_color = cli_args.get_bool("--pick-color");
+    let no_upload = cli_args.get_bool("--no-upload") || pick_color;
+    let early_copy = !cli_args.get_bool("--no-early-copy") && !no_upload;
+    let open_result = cli_args.get_bool("--open");
+    let watch = !cli_args.get_bool("--no-watch");
+    let capture_options = ncscreenier::CaptureOptions {
+        fps: match cli_args.get_str("--fps") {
+            "" => None,
+            fps => Some(fps.parse().unwrap_or_else(|_| {
+                docopt::Error::Argv("--fps must be a number".to_string()).exit()
+            })),
+        },
+        min_size: match cli_args.get_str("--min-size") {
+            "" => None,
+            min_size => Some(parse_size(min_size).unwrap_or_else(|| {
+                docopt::Error::Argv("--min-size must look like 16x16".to_string()).exit()
+            })),
+        },
+        selection: if cli_args.get_bool("--full") {
+            ncscreenier::Selection::Full
+        } else {
+            match cli_args.get_str("--region") {
+                "" => ncscreenier::Selection::Interactive,
+                region => parse_region(region).unwrap_or_else(|| {
+                    docopt::Error::Argv("--region must look like 0,0,800,600".to_string()).exit()
+                }),
+            }
+        },
+    };
+    let save_options = ncscreenier::SaveOptions {
+        loops: cli_args
+            .get_str("--loops")
+            .parse()
+            .unwrap_or_else(|_| docopt::Error::Argv("--loops must be a number".to_string()).exit()),
+        last_frame_fallback: cli_args.get_bool("--fallback-last"),
+        upscale_small_crops: cli_args.get_bool("--upscale-small-crops"),
+        strip_metadata: cli_args.get_bool("--strip-exif"),
+    };
+
+    if cli_args.get_bool("--delete-last") {
+        let deleted = ncscreenier::delete_last_upload(&last_upload_path, &config);
+        if json_output {
+            println!("{}", json!({ "deleted": deleted }));
+        }
+        std::process::exit(if deleted { 0 } else { 1 });
+    }
 
-    let mut ctx: ClipboardContext = ClipboardProvider::new().unwrap();
-    let mut runtime = move || {
-        if let Some(filename) = screenshot_and_save(&directory) {
-            if let Some(url) = upload_to_nebtown(
-                filename.as_str(),
-                format!("{}{}", directory, filename).as_str(),
-                account.as_str(),
-                4,
-            ) {
-                ctx.set_contents(url).unwrap();
+    // Headless runs that don't upload have nothing to copy, and may have no clipboard at all
+    let mut ctx: Option<ClipboardContext> = if no_upload && !pick_color {
+        None
+    } else {
+        match ClipboardProvider::new() {
+            Ok(ctx) => Some(ctx),
+            Err(e) => {
+                complain!("Clipboard unavailable, links won't be copied: {}", e);
+                None
+            }
+        }
+    };
+    // returns false if the capture failed, so one-off runs can exit non-zero
+    let runtime = move |full_screen: bool| -> bool {
+        let capture_options = if full_screen {
+            ncscreenier::CaptureOptions {
+                selection: ncscreenier::Selection::Full,
+                ..capture_options
+            }
+        } else {
+            capture_options
+        };
+        if pick_color {
+            let color = ncscreenier::capture_screenshot(&capture_options)
+                .and_then(|screenshot| ncscreenier::pick_color(&screenshot));
+            match color {
+                Ok(Some(color)) => {
+                    let hex = ncscreenier::to_hex(color);
+                    sayln!("Picked {}", hex);
+                    if json_output {
+                        println!("{}", json!({ "color": hex }));
+                    }
+                    set_clipboard(&mut ctx, hex);
+                }
+                Ok(None) => {
+                    sayln!("Closing screenshot due to right click");
+                    report_cancelled(json_output);
+                }
+                Err(e) => {
+                    report_failure(json_output, &e);
+                    return false;
+                }
+            }
+            return true;
+        }
+        let saved = match ncscreenier::screenshot_and_save(&directory, &capture_options, &save_options) {
+            Ok(saved) => saved,
+            Err(e) => {
+                report_failure(json_output, &e);
+                return false;
+            }
+        };
+        if let Some(saved) = saved {
+            if early_copy {
+                // The final url is known before the upload finishes, so it can be pasted straight away;
+                // the trailing '?' marks it as still pending, and is harmless once the upload lands.
+                let pending_url = account.file_url(saved.filename.as_str());
+                set_clipboard(&mut ctx, format!("{}?", pending_url));
+            }
+            let (url, upload_error) = if no_upload {
+                (None, None)
+            } else {
+                match ncscreenier::upload(saved.filename.as_str(), &saved.filepath, &account, 4) {
+                    Ok(url) => (Some(url), None),
+                    Err(e) => (None, Some(e)),
+                }
+            };
+            if let Some(url) = &url {
+                set_clipboard(&mut ctx, copy_format.format(url));
+                ncscreenier::remember_upload(&last_upload_path, &saved, account_name.as_str(), url);
             }
+            if open_result {
+                let target = match &url {
+                    Some(url) => url.clone(),
+                    None => saved.filepath.to_string_lossy().into_owned(),
+                };
+                // launchers like xdg-open can take a while to return, so don't hold up the
+                // hotkey listener; a one-off capture waits though, or exiting would cut it short
+                if watch {
+                    thread::spawn(move || open_target(&target));
+                } else {
+                    open_target(&target);
+                }
+            }
+            if json_output {
+                let mut result = json!({
+                    "filename": saved.filename,
+                    "filepath": saved.filepath.to_string_lossy(),
+                    "url": url,
+                    "width": saved.width,
+                    "height": saved.height,
+                    "frames": saved.frames,
+                    "bytes": saved.bytes,
+                });
+                // a failed upload still saved the file locally, so report both
+                if let Some(e) = &upload_error {
+                    result["error"] = json!(e.to_string());
+                }
+                println!("{}", result);
+            }
+            upload_error.is_none()
+        } else {
+            report_cancelled(json_output);
+            true
         }
     };
 
+    // both hotkeys share the one runtime, which also stops their captures from overlapping
+    let runtime = Arc::new(Mutex::new(runtime));
+
     let printscreen_hook;
-    if !cli_args.get_bool("--no-watch") {
+    if watch {
         printscreen_hook = livesplit_hotkey::Hook::new().unwrap();
+        let crop_runtime = runtime.clone();
         printscreen_hook
-            .register(PRINTSCREEN_KEYCODE, runtime)
+            .register(PRINTSCREEN_KEYCODE, move || {
+                (*crop_runtime.lock().unwrap())(false);
+            })
             .unwrap();
 
-        println!("ncscreenier listening for printscreen's...");
+        sayln!("ncscreenier listening for printscreen's...");
+
+        match cli_args.get_str("--full-screen-key") {
+            "" => {}
+            key_name => {
+                let key = parse_key(key_name).unwrap_or_else(|| {
+                    docopt::Error::Argv(format!("Unsupported --full-screen-key '{}'", key_name))
+                        .exit()
+                });
+                let full_screen_runtime = runtime.clone();
+                printscreen_hook
+                    .register(key, move || {
+                        (*full_screen_runtime.lock().unwrap())(true);
+                    })
+                    .unwrap();
+                sayln!("...and {} for whole desktop captures", key_name);
+            }
+        }
 
         if cli_args.get_bool("--quiet") {
             #[cfg(windows)]
@@ -135,497 +319,100 @@ Options:
         }
 
         sleep_until_exit();
-        println!("Exiting...");
-    } else {
-        runtime();
+        sayln!("Exiting...");
+    } else if !(*runtime.lock().unwrap())(false) {
+        std::process::exit(1);
     }
 }
 
-fn sleep_until_exit() {
-    let running = Arc::new(AtomicBool::new(true));
-    let r = running.clone();
-    ctrlc::set_handler(move || {
-        r.store(false, Ordering::SeqCst);
-    })
-    .expect("Error setting Ctrl-C handler");
-    while running.load(Ordering::SeqCst) {
-        thread::sleep(std::time::Duration::from_millis(100));
+// every capture gets one json line, even one the user right clicked away
+fn report_cancelled(json_output: bool) {
+    if json_output {
+        println!("{}", json!({ "cancelled": true }));
     }
 }
 
-fn screenshot_and_save(directory: &str) -> Option<String> {
-    let mut screenshot = capture_screenshot();
-
-    if let Some(rect) = present_for_cropping(&screenshot) {
-        let filename = format!("{}.png", chrono::Local::now().format("%Y_%m_%d_%H-%M-%S"));
-        let filepath = format!("{}{}", directory, filename);
-        print!(
-            "Saving crop {},{} -> {}, {} to {}...",
-            rect.top_left.0, rect.top_left.1, rect.bottom_right.0, rect.bottom_right.1, filepath
-        );
-        let cropped_width = rect.bottom_right.0 - rect.top_left.0;
-        let cropped_height = rect.bottom_right.1 - rect.top_left.1;
-        if screenshot.additional_images.len() == 0 {
-            let cropped_image: RgbImage = image::imageops::crop(
-                screenshot.image.borrow_mut(),
-                rect.top_left.0,
-                rect.top_left.1,
-                cropped_width,
-                cropped_height,
-            )
-            .to_image()
-            .convert();
-
-            let mut png_buffer = Vec::new();
-            let (width, height) = cropped_image.dimensions();
-            PNGEncoder::new(png_buffer.by_ref())
-                .encode(&cropped_image.into_raw(), width, height, ColorType::Rgb8)
-                .expect("error encoding pixels as PNG");
-
-            let mut oxipng_options = oxipng::Options::from_preset(2);
-            oxipng_options.verbosity = None;
-            let optimized_buffer = oxipng::optimize_from_memory(&png_buffer, &oxipng_options)
-                .expect("error optimizing png");
-
-            let mut file = File::create(&filepath).unwrap();
-            file.write_all(&optimized_buffer)
-                .expect("error writing png");
-        } else {
-            let mut file = File::create(&filepath).unwrap();
-            let mut encoder = Encoder::create(
-                &mut file,
-                Meta {
-                    color: Color::RGB(8),
-                    frames: 1 + (screenshot.additional_images.len() as u32),
-                    width: cropped_width,
-                    height: cropped_height,
-                    plays: None,
-                },
-            )
-            .expect("failed to create apng encoder");
-
-            let mut delays = screenshot.delays.into_iter();
-            std::iter::once(screenshot.image)
-                .chain(screenshot.additional_images.into_iter())
-                .for_each(|mut frame_image| {
-                    let cropped_frame: RgbImage = image::imageops::crop(
-                        frame_image.borrow_mut(),
-                        rect.top_left.0,
-                        rect.top_left.1,
-                        cropped_width,
-                        cropped_height,
-                    )
-                    .to_image()
-                    .convert();
-                    encoder
-                        .write_frame(
-                            &cropped_frame.into_raw(),
-                            Some(&Frame {
-                                delay: Some(Delay {
-                                    numerator: delays.next().unwrap(),
-                                    denominator: 1000,
-                                }),
-                                ..Default::default()
-                            }),
-                            None,
-                            None,
-                        )
-                        .unwrap();
-                });
-            encoder.finish().unwrap();
-        }
-        println!(" saved.");
-        Some(filename)
-    } else {
-        println!("Closing screenshot due to right click");
-        None
+fn report_failure(json_output: bool, e: &ncscreenier::Error) {
+    complain!("Screenshot failed: {}", e);
+    if json_output {
+        println!("{}", json!({ "error": e.to_string() }));
     }
 }
 
-fn upload_to_nebtown(
-    filename: &str,
-    filepath: &str,
-    directory: &str,
-    retries: u8,
-) -> Option<String> {
-    let url = format!("http://nebtown.info/ss/{}/{}", directory, filename);
-    print!("Uploading to {} ...", url);
-    stdout().flush().expect("error flushing stdout");
-    let mut ctx: ClipboardContext = ClipboardProvider::new().unwrap();
-    ctx.set_contents(format!("{}?", url)).unwrap();
-
-    let form = reqwest::multipart::Form::new()
-        .file("file", &filepath)
-        .unwrap();
-    let mut res = match reqwest::Client::new()
-        .post(&format!(
-            "http://nebtown.info/ss/?folder_name={}&file_name={}",
-            directory, filename
-        ))
-        .multipart(form)
-        .send()
-    {
-        Ok(success_response) => success_response,
-        Err(e) => {
-            println!(" upload error! {:?}", e);
-            return if retries > 0 {
-                std::thread::sleep(Duration::from_secs(max((5 - retries).into(), 1)));
-                upload_to_nebtown(filename, filepath, directory, retries - 1)
-            } else {
-                println!("Upload failed, giving up :(");
-                None
-            };
+// All clipboard writes share the one provider; some Linux backends lose the contents
+// on exit if several providers get created
+fn set_clipboard(ctx: &mut Option<ClipboardContext>, contents: String) {
+    if let Some(ctx) = ctx {
+        if let Err(e) = ctx.set_contents(contents) {
+            complain!("Couldn't copy to clipboard: {}", e);
         }
-    };
-    if res.status() == 200 {
-        println!(" done!");
-        Some(url)
-    } else {
-        println!(" error! {:?}, {:?}", res.status(), res.headers());
-        println!("{:?}", res.text().unwrap_or("??".to_string()));
-        None
     }
 }
 
-struct Rect {
-    top_left: (u32, u32),
-    bottom_right: (u32, u32),
-}
-
-fn present_for_cropping(screenshot: &PresentabeScreenshot) -> Option<Rect> {
-    let mut start_pos: (f64, f64) = (0.0, 0.0);
-    let mut last_pos: (f64, f64) = (0.0, 0.0);
-    let mut is_mouse_down = false;
-
-    let draw_width = screenshot.image.width();
-    let draw_height = screenshot.image.height() - 1; // if we're perfectly matching on Windows, it'll become a 'fullscreen app' that takes seconds to load
-    let mut window: PistonWindow = WindowSettings::new("NCScreenier", [draw_width, draw_height])
-        .exit_on_esc(true)
-        .decorated(false)
-        .resizable(false)
-        .fullscreen(false)
-        .vsync(true)
-        .build()
-        .unwrap();
-    window.set_position(piston_window::Position {
-        x: screenshot.x,
-        y: screenshot.y,
-    });
-    window.set_lazy(true);
-
-    // window.window.window.set_always_on_top(true); // not in latest version of piston_window >.>
-    let dpi_factor = EventsLoop::new().get_primary_monitor().get_hidpi_factor();
-    if dpi_factor != 1.0 {
-        d!(println!("dpi factor {:?}", dpi_factor));
-        window.set_size([
-            (draw_width as f64 / dpi_factor) as u32,
-            (draw_height as f64 / dpi_factor) as u32,
-        ]);
+fn open_target(target: &str) {
+    match open::that(target) {
+        Ok(status) if !status.success() => complain!("Couldn't open {}: {}", target, status),
+        Ok(_) => {}
+        Err(e) => complain!("Couldn't open {}: {}", target, e),
     }
-
-    let screenshot_texture: G2dTexture = if dpi_factor == 1.0 {
-        Texture::from_image(
-            &mut window.create_texture_context(),
-            &screenshot.image,
-            &TextureSettings::new(),
-        )
-        .unwrap()
-    } else {
-        Texture::from_image(
-            &mut window.create_texture_context(),
-            &image::imageops::resize(
-                &screenshot.image,
-                (draw_width as f64 / dpi_factor) as u32,
-                (screenshot.image.height() as f64 / dpi_factor) as u32,
-                FilterType::Lanczos3,
-            ),
-            &TextureSettings::new(),
-        )
-        .unwrap()
-    };
-
-    while let Some(e) = window.next() {
-        let e: piston_window::Event = e;
-
-        window.draw_2d(&e, |c, gl, _device| {
-            image(&screenshot_texture, c.transform, gl);
-            if start_pos.0 < last_pos.0 && start_pos.1 < last_pos.1 {
-                rectangle::Rectangle::new_border(SELECTION_COLOUR, 1.0).draw(
-                    rectangle::rectangle_by_corners(
-                        start_pos.0.into(),
-                        start_pos.1.into(),
-                        last_pos.0.into(),
-                        last_pos.1.into(),
-                    ),
-                    &draw_state::DrawState::default(),
-                    c.transform,
-                    gl,
-                );
-            }
-        });
-        if let Some(Button::Mouse(MouseButton::Right)) = e.press_args() {
-            window.set_should_close(true); // doesn't seem to be working
-            window.hide();
-            return None;
-        }
-        if let Some(Button::Mouse(MouseButton::Left)) = e.press_args() {
-            is_mouse_down = true;
-        }
-        if is_mouse_down {
-            if start_pos == (0.0, 0.0) {
-                e.mouse_cursor(|[x, y]| {
-                    start_pos = (x, y);
-                    d!(println!("start position {}, {}", x, y));
-                });
-            }
-            if let Some(ending) = e.release(|button| {
-                if button == Button::Mouse(MouseButton::Left) {
-                    is_mouse_down = false;
-                    if last_pos.0 > start_pos.0 && last_pos.1 > start_pos.1 {
-                        return true;
-                    } else {
-                        start_pos = (0.0, 0.0);
-                        last_pos = (0.0, 0.0);
-                    }
-                }
-                false
-            }) {
-                if ending {
-                    window.set_should_close(true); // doesn't seem to be working
-                    window.hide();
-                    return Some(Rect {
-                        top_left: (
-                            (start_pos.0 * dpi_factor) as u32,
-                            (start_pos.1 * dpi_factor) as u32,
-                        ),
-                        bottom_right: (
-                            (last_pos.0 * dpi_factor) as u32,
-                            (last_pos.1 * dpi_factor) as u32,
-                        ),
-                    });
-                } else {
-                    continue;
-                }
-            }
-            e.mouse_cursor(|[x, y]| {
-                last_pos = (x.max(0.0), y.max(0.0));
-            });
-        }
-    }
-    None
 }
 
-struct CapturerPosition {
-    capturer: Capturer,
-    top: i32,
-    left: i32,
+fn parse_size(size: &str) -> Option<(u32, u32)> {
+    let mut parts = size.splitn(2, 'x');
+    let width = parts.next()?.trim().parse().ok()?;
+    let height = parts.next()?.trim().parse().ok()?;
+    Some((width, height))
 }
 
-struct SubImage {
-    image: Option<image::RgbaImage>,
-    top: i32,
-    left: i32,
-    w: u32,
-    h: u32,
-}
-
-struct PresentabeScreenshot {
-    image: image::RgbaImage,
-    additional_images: Vec<RgbaImage>,
-    delays: Vec<u16>,
-    x: i32,
-    y: i32,
-}
-
-fn capture_screenshot() -> PresentabeScreenshot {
-    let displays: Vec<Display> = Display::all().expect("Couldn't get displays.");
-    let max_x = {
-        let display = displays
-            .iter()
-            .max_by(|x, y| x.right().cmp(&y.right()))
-            .unwrap();
-        display.right()
-    };
-    let min_x = {
-        let display = displays
-            .iter()
-            .min_by(|x, y| x.left().cmp(&y.left()))
-            .unwrap();
-        display.left()
-    };
-    let max_y = {
-        let display = displays
-            .iter()
-            .max_by(|x, y| x.bottom().cmp(&y.bottom()))
-            .unwrap();
-        display.bottom()
-    };
-    let min_y = {
-        let display = displays
-            .iter()
-            .min_by(|x, y| x.top().cmp(&y.top()))
-            .unwrap();
-        display.top()
-    };
-    d!(println!(
-        "Capturing screenshot with dimensions: {},{} {},{}",
-        min_x, min_y, max_x, max_y
-    ));
-
-    let capturers: Vec<RefCell<CapturerPosition>> = displays
-        .into_iter()
-        .map(|display| {
-            RefCell::new(CapturerPosition {
-                left: display.left(),
-                top: display.top(),
-                capturer: Capturer::new(display).expect("Couldn't begin capture"),
-            })
-        })
-        .collect();
-    let mut prev_frame_time = SystemTime::now();
-    let big_image = capture_image(&capturers, min_x, min_y, max_x, max_y, None);
-
-    let mut additional_images: Vec<RgbaImage> = Vec::new();
-    let mut delays: Vec<u16> = vec![SystemTime::now()
-        .duration_since(prev_frame_time)
-        .unwrap()
-        .as_millis() as u16];
-    prev_frame_time = SystemTime::now();
-
-    let device_state = DeviceState::new();
-    while device_state
-        .get_keys()
-        .into_iter()
-        .any(|key| key == Keycode::LShift || key == Keycode::RShift)
-    {
-        // std::thread::sleep_ms(50);
-        d!(print_time("Before additional image"));
-        additional_images.push(capture_image(
-            &capturers,
-            min_x,
-            min_y,
-            max_x,
-            max_y,
-            Some(additional_images.last().unwrap_or(&big_image)),
-        ));
-        delays.push(
-            SystemTime::now()
-                .duration_since(prev_frame_time)
-                .unwrap()
-                .as_millis() as u16,
-        );
-        prev_frame_time = SystemTime::now();
+fn parse_region(region: &str) -> Option<ncscreenier::Selection> {
+    let parts: Vec<&str> = region.split(',').map(|part| part.trim()).collect();
+    if parts.len() != 4 {
+        return None;
     }
-
-    return PresentabeScreenshot {
-        image: big_image,
-        additional_images,
-        delays,
-        x: min_x,
-        y: min_y,
-    };
+    Some(ncscreenier::Selection::Region {
+        x: parts[0].parse().ok()?,
+        y: parts[1].parse().ok()?,
+        width: parts[2].parse().ok()?,
+        height: parts[3].parse().ok()?,
+    })
 }
 
-fn capture_image(
-    capturers: &Vec<RefCell<CapturerPosition>>,
-    min_x: i32,
-    min_y: i32,
-    max_x: i32,
-    max_y: i32,
-    base_image: Option<&RgbaImage>,
-) -> RgbaImage {
-    let mut big_image = image::RgbaImage::new((max_x - min_x) as u32, (max_y - min_y) as u32);
-    d!(print_time("initialized image"));
-
-    capturers
-        .iter()
-        .map(|capturer_position_cell| {
-            let mut capturer_position = capturer_position_cell.borrow_mut();
-            let w = capturer_position.capturer.width();
-            let h = capturer_position.capturer.height();
-            let mut frames_asleep = 0;
-            loop {
-                match capturer_position.capturer.frame() {
-                    Ok(captured_buffer) => {
-                        if !captured_buffer.to_vec().iter().any(|&x| x != 0) {
-                            // sometimes it captures all black?? skip
-                            d!(println!("black frame"));
-                            thread::sleep(*DURATION_1MS);
-                            continue;
-                        }
-                        return SubImage {
-                            image: Some(scrap_buffer_to_rgbaimage(w, h, captured_buffer)),
-                            top: capturer_position.top,
-                            left: capturer_position.left,
-                            w: w as u32,
-                            h: h as u32,
-                        };
-                    }
-                    Err(error) => {
-                        if error.kind() == WouldBlock {
-                            if frames_asleep > 20 && base_image.is_some() {
-                                return SubImage {
-                                    image: None,
-                                    top: capturer_position.top,
-                                    left: capturer_position.left,
-                                    w: w as u32,
-                                    h: h as u32,
-                                };
-                            }
-                            // Wait until there's a frame.
-                            d!(println!("would block {:?}", frames_asleep));
-                            frames_asleep += 1;
-                            //thread::sleep(*DURATION_1MS);
-                            continue;
-                        } else {
-                            panic!("Error: {}", error);
-                        }
-                    }
-                };
-            }
-        })
-        .for_each(|subimage| {
-            if subimage.image.is_none() {
-                big_image.copy_from(
-                    &(base_image.unwrap().view(
-                        (subimage.left - min_x) as u32,
-                        (subimage.top - min_y) as u32,
-                        subimage.w,
-                        subimage.h,
-                    )),
-                    (subimage.left - min_x) as u32,
-                    (subimage.top - min_y) as u32,
-                ).unwrap();
-            } else {
-                big_image.copy_from(
-                    &subimage.image.unwrap(),
-                    (subimage.left - min_x) as u32,
-                    (subimage.top - min_y) as u32,
-                ).unwrap();
-            }
-        });
-    big_image
+#[cfg(windows)]
+fn parse_key(name: &str) -> Option<KeyCode> {
+    Some(match name {
+        "Pause" => KeyCode::Pause,
+        "ScrollLock" => KeyCode::Scroll,
+        "Insert" => KeyCode::Insert,
+        "F9" => KeyCode::F9,
+        "F10" => KeyCode::F10,
+        "F11" => KeyCode::F11,
+        "F12" => KeyCode::F12,
+        _ => return None,
+    })
 }
 
-fn scrap_buffer_to_rgbaimage(w: usize, h: usize, buffer: scrap::Frame) -> image::RgbaImage {
-    // Flip the ARGB image into a BGRA image.
-    let mut bitflipped = Vec::with_capacity(w * h * 4);
-    let stride = buffer.len() / h;
-    for y in 0..h {
-        for x in 0..w {
-            let i = stride * y + 4 * x;
-            bitflipped.extend_from_slice(&[buffer[i + 2], buffer[i + 1], buffer[i], 255]);
-        }
-    }
-    image::RgbaImage::from_raw(w as u32, h as u32, bitflipped).unwrap()
+#[cfg(not(windows))]
+fn parse_key(name: &str) -> Option<KeyCode> {
+    Some(match name {
+        "Pause" => KeyCode::Pause,
+        "ScrollLock" => KeyCode::ScrollLock,
+        "Insert" => KeyCode::Insert,
+        "F9" => KeyCode::F9,
+        "F10" => KeyCode::F10,
+        "F11" => KeyCode::F11,
+        "F12" => KeyCode::F12,
+        _ => return None,
+    })
 }
 
-fn print_time(s: &str) {
-    println!(
-        "{:<20}: {:?}",
-        s,
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards")
-    );
+fn sleep_until_exit() {
+    let running = Arc::new(AtomicBool::new(true));
+    let r = running.clone();
+    ctrlc::set_handler(move || {
+        r.store(false, Ordering::SeqCst);
+    })
+    .expect("Error setting Ctrl-C handler");
+    while running.load(Ordering::SeqCst) {
+        thread::sleep(std::time::Duration::from_millis(100));
+    }
 }